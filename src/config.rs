@@ -0,0 +1,50 @@
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+
+#[derive(Clone)]
+pub struct Config {
+    pub interface: Option<String>,
+    pub ssid: String,
+    pub passphrase: Option<String>,
+    pub gateway: Ipv4Addr,
+    pub ui_directory: PathBuf,
+    pub activity_timeout: u64,
+    /// When set, dnsmasq answers every DNS query with the portal gateway and
+    /// the server redirects the OS captive-portal probes, so the "Sign in to
+    /// network" prompt appears automatically instead of requiring the user
+    /// to browse to the gateway manually.
+    pub captive_portal: bool,
+    /// Extra inputs to render on the portal form alongside SSID/passphrase,
+    /// e.g. a device name or an API token an integrator wants collected
+    /// during onboarding.
+    pub extra_fields: Vec<ExtraField>,
+    /// Where the values collected for `extra_fields` are written once a
+    /// connection succeeds. `None` means print them to stdout instead.
+    pub output_path: Option<PathBuf>,
+}
+
+/// Describes one custom input rendered on the portal form, mirroring
+/// WiFiManager's `WiFiManagerParameter`.
+#[derive(Clone)]
+pub struct ExtraField {
+    pub id: String,
+    pub label: String,
+    pub default: Option<String>,
+    pub max_length: Option<usize>,
+}
+
+impl Config {
+    pub fn get_config() -> Config {
+        Config {
+            interface: None,
+            ssid: "WiFi Connect".to_string(),
+            passphrase: None,
+            gateway: Ipv4Addr::new(192, 168, 42, 1),
+            ui_directory: PathBuf::from("ui"),
+            activity_timeout: 600,
+            captive_portal: true,
+            extra_fields: Vec::new(),
+            output_path: None,
+        }
+    }
+}
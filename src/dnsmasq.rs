@@ -0,0 +1,53 @@
+use std::io;
+use std::net::Ipv4Addr;
+use std::process::{Child, Command, Stdio};
+
+use network_manager::Device;
+
+use config::Config;
+
+pub fn start_dnsmasq(config: &Config, device: &Device) -> Result<Child, io::Error> {
+    let args = get_dnsmasq_args(config, device);
+
+    Command::new("dnsmasq")
+        .args(&args)
+        .stdout(Stdio::null())
+        .spawn()
+}
+
+fn get_dnsmasq_args(config: &Config, device: &Device) -> Vec<String> {
+    let gateway = config.gateway;
+
+    let mut args = vec![
+        "--no-hosts".to_string(),
+        "--keep-in-foreground".to_string(),
+        format!("--interface={}", device.interface()),
+        "--except-interface=lo".to_string(),
+        "--bind-interfaces".to_string(),
+        format!(
+            "--dhcp-range={},{},255.255.255.0,24h",
+            first_host(gateway),
+            last_host(gateway)
+        ),
+        format!("--dhcp-option=option:router,{}", gateway),
+    ];
+
+    if config.captive_portal {
+        // Resolve every hostname to the portal gateway so that the OS
+        // "Sign in to network" probe (and any other DNS lookup made while
+        // connected to the hotspot) lands on the portal instead of failing.
+        args.push(format!("--address=/#/{}", gateway));
+    }
+
+    args
+}
+
+fn first_host(gateway: Ipv4Addr) -> Ipv4Addr {
+    let octets = gateway.octets();
+    Ipv4Addr::new(octets[0], octets[1], octets[2], 2)
+}
+
+fn last_host(gateway: Ipv4Addr) -> Ipv4Addr {
+    let octets = gateway.octets();
+    Ipv4Addr::new(octets[0], octets[1], octets[2], 254)
+}
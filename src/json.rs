@@ -0,0 +1,21 @@
+/// Minimal JSON string escaping, used wherever arbitrary user input (form
+/// values, SSIDs) needs to be embedded inside a JSON string literal.
+/// `{:?}`/Debug formatting is not a JSON serializer (its escapes, e.g.
+/// `\u{1b}`, aren't valid JSON), so this exists instead of reaching for it.
+pub fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
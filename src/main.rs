@@ -0,0 +1,43 @@
+#[macro_use]
+extern crate log;
+extern crate network_manager;
+
+use std::sync::mpsc::{channel, Sender};
+
+mod config;
+mod dnsmasq;
+mod json;
+mod network;
+mod server;
+
+use config::Config;
+use network::{init_networking, process_network_commands};
+
+pub type ExitResult = Result<(), String>;
+
+pub fn exit(exit_tx: &Sender<ExitResult>, error: String) {
+    let _ = exit_tx.send(Err(error));
+}
+
+fn main() {
+    let config = Config::get_config();
+
+    init_networking();
+
+    let (exit_tx, exit_rx) = channel();
+
+    process_network_commands(&config, &exit_tx);
+
+    match exit_rx.recv() {
+        Ok(result) => {
+            if let Err(reason) = result {
+                error!("{}", reason);
+                ::std::process::exit(1);
+            }
+        },
+        Err(e) => {
+            error!("Exit receiver closed unexpectedly: {}", e);
+            ::std::process::exit(1);
+        },
+    }
+}
@@ -4,23 +4,76 @@ use std::time::Duration;
 use std::sync::mpsc::{channel, Sender};
 use std::error::Error;
 use std::net::Ipv4Addr;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
 
 use network_manager::{AccessPoint, Connection, ConnectionState, Connectivity, Device, DeviceType,
-                      NetworkManager, ServiceState};
+                      NetworkManager, Security, ServiceState};
 
 use {exit, ExitResult};
 use config::Config;
 use dnsmasq::start_dnsmasq;
+use json;
 use server::start_server;
 
 pub enum NetworkCommand {
     Activate,
     Timeout,
-    Connect { ssid: String, passphrase: String },
+    Rescan,
+    Connect {
+        ssid: String,
+        passphrase: String,
+        extra_fields: Vec<(String, String)>,
+        eap: Option<EapConfig>,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub enum EapMethod {
+    Peap,
+    Ttls,
+    Tls,
+}
+
+/// Credentials for an 802.1x / EAP (WPA-Enterprise) network, generalizing
+/// the plain PSK passphrase the way shill's `eap_credentials` does.
+#[derive(Clone, Debug)]
+pub struct EapConfig {
+    pub method: EapMethod,
+    pub identity: String,
+    pub anonymous_identity: Option<String>,
+    pub ca_cert_path: Option<String>,
 }
 
 pub enum NetworkCommandResponse {
-    AccessPointsSsids(Vec<String>),
+    AccessPoints(Vec<AccessPointInfo>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AccessPointSecurity {
+    Open,
+    Wpa,
+    Enterprise,
+}
+
+impl AccessPointSecurity {
+    fn from_security(security: Security) -> AccessPointSecurity {
+        if security.contains(Security::ENTERPRISE) {
+            AccessPointSecurity::Enterprise
+        } else if security.is_empty() {
+            AccessPointSecurity::Open
+        } else {
+            AccessPointSecurity::Wpa
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AccessPointInfo {
+    pub ssid: String,
+    pub strength: u32,
+    pub security: AccessPointSecurity,
 }
 
 #[cfg_attr(feature = "cargo-clippy", allow(cyclomatic_complexity))]
@@ -42,6 +95,12 @@ pub fn process_network_commands(config: &Config, exit_tx: &Sender<ExitResult>) {
         },
     };
 
+    if find_and_activate_known_network(&manager, &device, &access_points) {
+        info!("Connected to a previously saved network; skipping the access point");
+        let _ = exit_tx.send(Ok(()));
+        return;
+    }
+
     let portal_ssid = &config.ssid;
     let portal_passphrase = config.passphrase.as_ref().map(|p| p as &str);
 
@@ -60,12 +119,11 @@ pub fn process_network_commands(config: &Config, exit_tx: &Sender<ExitResult>) {
 
     let exit_tx_server = exit_tx.clone();
     let network_tx_timeout = network_tx.clone();
-    let gateway = config.gateway;
-    let ui_directory = config.ui_directory.clone();
+    let server_config = config.clone();
     let activity_timeout = config.activity_timeout;
 
     thread::spawn(move || {
-        start_server(gateway, server_rx, network_tx, exit_tx_server, &ui_directory);
+        start_server(&server_config, server_rx, network_tx, exit_tx_server);
     });
 
     if config.activity_timeout != 0 {
@@ -100,10 +158,10 @@ pub fn process_network_commands(config: &Config, exit_tx: &Sender<ExitResult>) {
             NetworkCommand::Activate => {
                 activated = true;
 
-                let access_points_ssids = get_access_points_ssids_owned(&access_points);
+                let access_points_info = get_access_points_owned(&access_points);
 
-                if let Err(e) = server_tx.send(NetworkCommandResponse::AccessPointsSsids(
-                    access_points_ssids,
+                if let Err(e) = server_tx.send(NetworkCommandResponse::AccessPoints(
+                    access_points_info,
                 )) {
                     return exit_with_error(
                         exit_tx,
@@ -117,6 +175,34 @@ pub fn process_network_commands(config: &Config, exit_tx: &Sender<ExitResult>) {
                     );
                 }
             },
+            NetworkCommand::Rescan => {
+                access_points = match rescan_access_points(&device) {
+                    Ok(access_points) => access_points,
+                    Err(e) => {
+                        return exit_with_error(
+                            exit_tx,
+                            dnsmasq,
+                            portal_connection,
+                            portal_ssid,
+                            format!("Rescanning access points failed: {}", e),
+                        );
+                    },
+                };
+
+                let access_points_info = get_access_points_owned(&access_points);
+
+                if let Err(e) =
+                    server_tx.send(NetworkCommandResponse::AccessPoints(access_points_info))
+                {
+                    return exit_with_error(
+                        exit_tx,
+                        dnsmasq,
+                        portal_connection,
+                        portal_ssid,
+                        format!("Sending rescanned access points failed: {}", e.description()),
+                    );
+                }
+            },
             NetworkCommand::Timeout => {
                 if activated == false {
                     info!("Timeout reached. Exiting...");
@@ -129,7 +215,7 @@ pub fn process_network_commands(config: &Config, exit_tx: &Sender<ExitResult>) {
                     );
                 }
             },
-            NetworkCommand::Connect { ssid, passphrase } => {
+            NetworkCommand::Connect { ssid, passphrase, extra_fields, eap } => {
                 if let Some(connection) = portal_connection {
                     let result = stop_portal(&connection, &config.ssid);
                     if let Err(e) = result {
@@ -158,14 +244,40 @@ pub fn process_network_commands(config: &Config, exit_tx: &Sender<ExitResult>) {
                 };
 
                 {
-                    let (access_point, access_point_ssid) =
-                        find_access_point(&access_points, &ssid).unwrap();
-
                     let wifi_device = device.as_wifi_device().unwrap();
 
-                    info!("Connecting to access point '{}'...", access_point_ssid);
+                    let connect_result = match find_access_point(&access_points, &ssid) {
+                        Some((access_point, access_point_ssid)) => {
+                            info!("Connecting to access point '{}'...", access_point_ssid);
+
+                            match eap {
+                                Some(ref eap) => connect_enterprise(
+                                    &manager,
+                                    &device,
+                                    Some(access_point),
+                                    &ssid,
+                                    false,
+                                    eap,
+                                ),
+                                None => wifi_device.connect(access_point, &passphrase as &str),
+                            }
+                        },
+                        None => {
+                            info!(
+                                "'{}' not found in scan results; joining as a hidden network...",
+                                ssid
+                            );
 
-                    match wifi_device.connect(access_point, &passphrase as &str) {
+                            match eap {
+                                Some(ref eap) => {
+                                    connect_enterprise(&manager, &device, None, &ssid, true, eap)
+                                },
+                                None => connect_hidden(&manager, &device, &ssid, &passphrase),
+                            }
+                        },
+                    };
+
+                    match connect_result {
                         Ok((connection, state)) => {
                             if state == ConnectionState::Activated {
                                 match wait_for_connectivity(&manager, 20) {
@@ -173,6 +285,15 @@ pub fn process_network_commands(config: &Config, exit_tx: &Sender<ExitResult>) {
                                         if has_connectivity {
                                             info!("Connectivity established");
 
+                                            if let Err(e) =
+                                                write_extra_fields(config, &extra_fields)
+                                            {
+                                                error!(
+                                                    "Writing extra portal fields failed: {}",
+                                                    e
+                                                );
+                                            }
+
                                             return exit_ok(
                                                 exit_tx,
                                                 dnsmasq,
@@ -193,13 +314,13 @@ pub fn process_network_commands(config: &Config, exit_tx: &Sender<ExitResult>) {
 
                             warn!(
                                 "Connection to access point not activated '{}': {:?}",
-                                access_point_ssid, state
+                                ssid, state
                             );
                         },
                         Err(e) => {
                             warn!(
                                 "Error connecting to access point '{}': {}",
-                                access_point_ssid, e
+                                ssid, e
                             );
                         },
                     }
@@ -301,6 +422,20 @@ fn get_access_points(device: &Device) -> Result<Vec<AccessPoint>, String> {
     Ok(vec![])
 }
 
+/// Re-reads the access point list instead of relying on whatever was cached
+/// when the portal started. This deliberately doesn't call a
+/// `request_scan`/`is_scanning`-style API to force a fresh scan first: the
+/// baseline never calls either, and there's no manifest in this tree to
+/// confirm them against the pinned `network_manager` crate, unlike
+/// `get_access_points`/`as_wifi_device`, which it already relies on.
+/// NetworkManager scans in the background on its own, so re-reading through
+/// `get_access_points` gets the freshest list this binary can read without
+/// guessing at another accessor.
+fn rescan_access_points(device: &Device) -> Result<Vec<AccessPoint>, String> {
+    info!("Re-reading access points for rescan...");
+    get_access_points(device)
+}
+
 fn get_access_points_ssids(access_points: &[AccessPoint]) -> Vec<&str> {
     access_points
         .iter()
@@ -308,11 +443,40 @@ fn get_access_points_ssids(access_points: &[AccessPoint]) -> Vec<&str> {
         .collect()
 }
 
-fn get_access_points_ssids_owned(access_points: &[AccessPoint]) -> Vec<String> {
-    access_points
-        .iter()
-        .map(|ap| ap.ssid().as_str().unwrap().to_string())
-        .collect()
+/// Converts the raw scan results into the deduplicated, strength-sorted list
+/// the portal UI renders. When the same SSID is seen more than once (common
+/// with multi-radio APs), the strongest reading wins.
+fn get_access_points_owned(access_points: &[AccessPoint]) -> Vec<AccessPointInfo> {
+    let mut by_ssid: HashMap<String, AccessPointInfo> = HashMap::new();
+
+    for access_point in access_points.iter() {
+        let ssid = match access_point.ssid().as_str() {
+            Ok(ssid) => ssid.to_string(),
+            Err(_) => continue,
+        };
+
+        // `frequency()` isn't something this file (or the baseline) ever
+        // called, and there's no manifest here to check it against the
+        // pinned network_manager crate, so it's dropped rather than risk
+        // shipping another unverifiable accessor.
+        let info = AccessPointInfo {
+            ssid: ssid.clone(),
+            strength: access_point.strength() as u32,
+            security: AccessPointSecurity::from_security(access_point.security()),
+        };
+
+        let stronger = by_ssid
+            .get(&ssid)
+            .map_or(true, |existing| info.strength > existing.strength);
+
+        if stronger {
+            by_ssid.insert(ssid, info);
+        }
+    }
+
+    let mut access_points_info: Vec<AccessPointInfo> = by_ssid.into_iter().map(|(_, v)| v).collect();
+    access_points_info.sort_by(|a, b| b.strength.cmp(&a.strength));
+    access_points_info
 }
 
 fn find_access_point<'a>(
@@ -330,6 +494,210 @@ fn find_access_point<'a>(
     None
 }
 
+/// Tries every access point currently in range that matches a saved
+/// NetworkManager connection profile, strongest signal first, so an
+/// unattended reboot can recover connectivity without creating the hotspot.
+fn find_and_activate_known_network(
+    manager: &NetworkManager,
+    device: &Device,
+    access_points: &[AccessPoint],
+) -> bool {
+    let known_connections = match manager.get_connections() {
+        Ok(connections) => connections,
+        Err(e) => {
+            warn!("Getting known connections failed: {}", e);
+            return false;
+        },
+    };
+
+    let mut candidates: Vec<&AccessPoint> = access_points.iter().collect();
+    candidates.sort_by(|a, b| b.strength().cmp(&a.strength()));
+
+    for access_point in candidates {
+        let ssid = match access_point.ssid().as_str() {
+            Ok(ssid) => ssid,
+            Err(_) => continue,
+        };
+
+        let known_connection = known_connections.iter().find(|connection| {
+            connection.settings().kind == "802-11-wireless"
+                && connection
+                    .settings()
+                    .ssid
+                    .as_str()
+                    .map(|known_ssid| known_ssid == ssid)
+                    .unwrap_or(false)
+        });
+
+        let connection = match known_connection {
+            Some(connection) => connection,
+            None => continue,
+        };
+
+        info!("Trying saved network '{}'...", ssid);
+
+        match device.activate_connection(connection) {
+            Ok(state) => {
+                if state == ConnectionState::Activated {
+                    match wait_for_connectivity(manager, 20) {
+                        Ok(true) => {
+                            info!("Connected to saved network '{}'", ssid);
+                            return true;
+                        },
+                        Ok(false) => warn!("Saved network '{}' has no connectivity", ssid),
+                        Err(e) => error!("Getting connectivity failed: {}", e),
+                    }
+                } else {
+                    warn!("Saved network '{}' not activated: {:?}", ssid, state);
+                }
+            },
+            Err(e) => warn!("Activating saved network '{}' failed: {}", ssid, e),
+        }
+    }
+
+    false
+}
+
+/// One NetworkManager D-Bus "variant" value. Real connection settings are
+/// `a{sa{sv}}` (each setting is typed, not a plain string) - `hidden` has to
+/// be an actual boolean and `ssid` an actual byte array, or NetworkManager
+/// rejects the connection outright, so this exists instead of flattening
+/// everything to `String` the way an early pass at this did.
+#[derive(Clone, Debug)]
+pub enum SettingValue {
+    Str(String),
+    Bool(bool),
+    Bytes(Vec<u8>),
+}
+
+type ConnectionSettings = HashMap<String, HashMap<String, SettingValue>>;
+
+/// Builds the `connection` + `802-11-wireless` blocks shared by both the
+/// hidden and enterprise connect paths below. `connection.id`/`.type` are
+/// mandatory on every NetworkManager connection, not just an SSID/security
+/// pair, so they're set here rather than left for NetworkManager to guess.
+fn build_wireless_settings(ssid: &str, hidden: bool) -> ConnectionSettings {
+    let mut connection = HashMap::new();
+    connection.insert("id".to_string(), SettingValue::Str(ssid.to_string()));
+    connection.insert(
+        "type".to_string(),
+        SettingValue::Str("802-11-wireless".to_string()),
+    );
+
+    let mut wireless = HashMap::new();
+    wireless.insert("ssid".to_string(), SettingValue::Bytes(ssid.as_bytes().to_vec()));
+    wireless.insert("hidden".to_string(), SettingValue::Bool(hidden));
+    wireless.insert(
+        "mode".to_string(),
+        SettingValue::Str("infrastructure".to_string()),
+    );
+
+    let mut settings = HashMap::new();
+    settings.insert("connection".to_string(), connection);
+    settings.insert("802-11-wireless".to_string(), wireless);
+    settings
+}
+
+fn build_psk_security_settings(passphrase: &str) -> ConnectionSettings {
+    let mut security = HashMap::new();
+    security.insert(
+        "key-mgmt".to_string(),
+        SettingValue::Str("wpa-psk".to_string()),
+    );
+    security.insert("psk".to_string(), SettingValue::Str(passphrase.to_string()));
+
+    let mut settings = HashMap::new();
+    settings.insert("802-11-wireless-security".to_string(), security);
+    settings
+}
+
+/// Builds the `802-11-wireless-security` + `802-1x` settings NetworkManager
+/// needs for an EAP connection, generalizing the PSK path the same way
+/// shill's `eap_credentials` does.
+fn build_eap_security_settings(eap: &EapConfig) -> ConnectionSettings {
+    let mut security = HashMap::new();
+    security.insert(
+        "key-mgmt".to_string(),
+        SettingValue::Str("wpa-eap".to_string()),
+    );
+
+    let mut eap_settings = HashMap::new();
+    eap_settings.insert(
+        "eap".to_string(),
+        SettingValue::Str(eap_method_name(&eap.method).to_string()),
+    );
+    eap_settings.insert("identity".to_string(), SettingValue::Str(eap.identity.clone()));
+
+    if let Some(ref anonymous_identity) = eap.anonymous_identity {
+        eap_settings.insert(
+            "anonymous-identity".to_string(),
+            SettingValue::Str(anonymous_identity.clone()),
+        );
+    }
+
+    if let Some(ref ca_cert_path) = eap.ca_cert_path {
+        eap_settings.insert(
+            "ca-cert".to_string(),
+            SettingValue::Str(ca_cert_path.clone()),
+        );
+    }
+
+    let mut settings = HashMap::new();
+    settings.insert("802-11-wireless-security".to_string(), security);
+    settings.insert("802-1x".to_string(), eap_settings);
+    settings
+}
+
+fn eap_method_name(method: &EapMethod) -> &'static str {
+    match *method {
+        EapMethod::Peap => "peap",
+        EapMethod::Ttls => "ttls",
+        EapMethod::Tls => "tls",
+    }
+}
+
+/// Joins a network with a non-broadcast SSID by building the connection
+/// settings directly (marking `802-11-wireless` as hidden and supplying the
+/// SSID/passphrase), instead of requiring a scan-result lookup that a
+/// never-advertised network will never satisfy.
+///
+/// `add_and_activate_connection` mirrors NetworkManager's own
+/// `AddAndActivateConnection` D-Bus call; there's no `Cargo.toml` in this
+/// tree to check it against the pinned `network_manager` crate, so treat
+/// the entry point itself as unconfirmed until that's verified, even
+/// though the settings shape built above now matches real NM semantics.
+fn connect_hidden(
+    manager: &NetworkManager,
+    device: &Device,
+    ssid: &str,
+    passphrase: &str,
+) -> Result<(Connection, ConnectionState), String> {
+    let mut settings = build_wireless_settings(ssid, true);
+    settings.extend(build_psk_security_settings(passphrase));
+
+    manager.add_and_activate_connection(settings, device, None)
+}
+
+/// Builds and activates an 802.1x (WPA-Enterprise) connection from the
+/// method/identity/anonymous-identity/CA-cert fields collected on the
+/// portal form. `access_point` is passed through when the network was
+/// found in the scan results, and left out for a hidden enterprise network.
+/// See the `add_and_activate_connection` note on `connect_hidden` above -
+/// same caveat applies here.
+fn connect_enterprise(
+    manager: &NetworkManager,
+    device: &Device,
+    access_point: Option<&AccessPoint>,
+    ssid: &str,
+    hidden: bool,
+    eap: &EapConfig,
+) -> Result<(Connection, ConnectionState), String> {
+    let mut settings = build_wireless_settings(ssid, hidden);
+    settings.extend(build_eap_security_settings(eap));
+
+    manager.add_and_activate_connection(settings, device, access_point)
+}
+
 fn create_portal(
     device: &Device,
     ssid: &str,
@@ -343,6 +711,40 @@ fn create_portal(
     Ok(portal_connection)
 }
 
+/// Writes the custom fields collected on the portal form so that the
+/// surrounding provisioning system can pick them up: to `config.output_path`
+/// if one was configured, otherwise as a line of JSON on stdout.
+fn write_extra_fields(config: &Config, extra_fields: &[(String, String)]) -> Result<(), String> {
+    if extra_fields.is_empty() {
+        return Ok(());
+    }
+
+    let json = extra_fields_to_json(extra_fields);
+
+    match config.output_path {
+        Some(ref path) => {
+            let mut file = File::create(path).map_err(|e| e.to_string())?;
+            file.write_all(json.as_bytes()).map_err(|e| e.to_string())
+        },
+        None => {
+            println!("{}", json);
+            Ok(())
+        },
+    }
+}
+
+fn extra_fields_to_json(extra_fields: &[(String, String)]) -> String {
+    let fields = extra_fields
+        .iter()
+        .map(|&(ref id, ref value)| {
+            format!("\"{}\":\"{}\"", json::escape(id), json::escape(value))
+        })
+        .collect::<Vec<String>>()
+        .join(",");
+
+    format!("{{{}}}", fields)
+}
+
 fn stop_portal(connection: &Connection, ssid: &str) -> Result<(), String> {
     info!("Stopping access point '{}'...", ssid);
     connection.deactivate()?;
@@ -0,0 +1,404 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{Receiver, Sender};
+
+use ExitResult;
+use config::Config;
+use json;
+use network::{AccessPointInfo, AccessPointSecurity, EapConfig, EapMethod, NetworkCommand,
+              NetworkCommandResponse};
+
+/// Paths the major mobile/desktop OSes hit right after associating with an
+/// access point, used to decide whether a captive portal needs to be shown.
+/// Answering these with anything other than the expected "all clear"
+/// response is what makes the "Sign in to network" prompt pop up on its own.
+const CAPTIVE_PORTAL_PROBE_PATHS: &[&str] = &[
+    "/generate_204",              // Android
+    "/gen_204",                   // Android (newer)
+    "/hotspot-detect.html",       // Apple
+    "/library/test/success.html", // Apple (older)
+    "/ncsi.txt",                  // Windows
+    "/connecttest.txt",           // Windows (newer)
+];
+
+pub fn start_server(
+    config: &Config,
+    server_rx: Receiver<NetworkCommandResponse>,
+    network_tx: Sender<NetworkCommand>,
+    exit_tx: Sender<ExitResult>,
+) {
+    let listener = match TcpListener::bind((config.gateway, 80)) {
+        Ok(listener) => listener,
+        Err(e) => {
+            let _ = exit_tx.send(Err(format!("Starting the portal server failed: {}", e)));
+            return;
+        },
+    };
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => handle_connection(stream, config, &server_rx, &network_tx),
+            Err(e) => error!("Accepting a portal connection failed: {}", e),
+        }
+    }
+}
+
+fn handle_connection(
+    stream: TcpStream,
+    config: &Config,
+    server_rx: &Receiver<NetworkCommandResponse>,
+    network_tx: &Sender<NetworkCommand>,
+) {
+    let mut reader = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).is_err() {
+        return;
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or("GET").to_string();
+    let path = parts.next().unwrap_or("/").to_string();
+
+    let mut content_length: usize = 0;
+
+    loop {
+        let mut header_line = String::new();
+        match reader.read_line(&mut header_line) {
+            Ok(0) => break,
+            Ok(_) => {
+                if header_line == "\r\n" || header_line == "\n" {
+                    break;
+                }
+
+                if header_line.to_lowercase().starts_with("content-length:") {
+                    if let Some(value) = header_line.splitn(2, ':').nth(1) {
+                        content_length = value.trim().parse().unwrap_or(0);
+                    }
+                }
+            },
+            Err(_) => break,
+        }
+    }
+
+    let mut body_bytes = vec![0; content_length];
+    if content_length > 0 && reader.read_exact(&mut body_bytes).is_err() {
+        return;
+    }
+    let body = String::from_utf8_lossy(&body_bytes).into_owned();
+
+    let mut stream = reader.into_inner();
+
+    if config.captive_portal && is_captive_portal_probe(&path) {
+        respond_with_portal_redirect(&mut stream, config);
+        return;
+    }
+
+    route(&method, &path, &body, &mut stream, config, server_rx, network_tx);
+}
+
+fn is_captive_portal_probe(path: &str) -> bool {
+    CAPTIVE_PORTAL_PROBE_PATHS.contains(&path)
+}
+
+/// Sends a redirect to the portal root. A real "204 No Content" (what the
+/// probes expect when there is internet access) would make the OS consider
+/// the network already usable and never show the sign-in prompt.
+fn respond_with_portal_redirect(stream: &mut TcpStream, config: &Config) {
+    let body = format!(
+        "HTTP/1.1 302 Found\r\nLocation: http://{}/\r\nContent-Length: 0\r\n\r\n",
+        config.gateway
+    );
+    let _ = stream.write_all(body.as_bytes());
+}
+
+fn route(
+    method: &str,
+    path: &str,
+    body: &str,
+    stream: &mut TcpStream,
+    config: &Config,
+    server_rx: &Receiver<NetworkCommandResponse>,
+    network_tx: &Sender<NetworkCommand>,
+) {
+    debug!("Portal request: {} {}", method, path);
+
+    match (method, path) {
+        ("GET", "/") => serve_portal_page(stream, config),
+        ("GET", "/networks") => {
+            serve_access_points(stream, server_rx, network_tx, NetworkCommand::Activate)
+        },
+        ("POST", "/networks/rescan") => {
+            serve_access_points(stream, server_rx, network_tx, NetworkCommand::Rescan)
+        },
+        ("POST", "/connect") => handle_connect(stream, body, config, network_tx),
+        _ => respond(stream, 404, "Not Found", ""),
+    }
+}
+
+fn serve_portal_page(stream: &mut TcpStream, config: &Config) {
+    let index_path = config.ui_directory.join("index.html");
+    let body = fs::read_to_string(&index_path).unwrap_or_else(|_| render_default_portal_page(config));
+
+    respond(stream, 200, "OK", &body);
+}
+
+/// Renders the SSID/passphrase inputs plus one input per configured
+/// `ExtraField`, mirroring WiFiManager's `WiFiManagerParameter` fields.
+fn render_default_portal_page(config: &Config) -> String {
+    let extra_inputs: String = config
+        .extra_fields
+        .iter()
+        .map(|field| {
+            let default = field.default.clone().unwrap_or_default();
+            let maxlength = field
+                .max_length
+                .map(|len| format!(" maxlength=\"{}\"", len))
+                .unwrap_or_default();
+
+            format!(
+                "  <input name=\"{id}\" placeholder=\"{label}\" value=\"{default}\"{maxlength}>\n",
+                id = html_escape(&field.id),
+                label = html_escape(&field.label),
+                default = html_escape(&default),
+                maxlength = maxlength
+            )
+        })
+        .collect();
+
+    let mut page = String::new();
+    page.push_str("<!DOCTYPE html>\n<html>\n<head><title>WiFi Connect</title></head>\n<body>\n");
+    page.push_str("<form method=\"POST\" action=\"/connect\">\n");
+    page.push_str("  <input name=\"ssid\" placeholder=\"Network name\">\n");
+    page.push_str("  <input name=\"passphrase\" type=\"password\" placeholder=\"Password\">\n");
+    page.push_str(&extra_inputs);
+    page.push_str(EAP_FORM_FIELDS);
+    page.push_str("  <button type=\"submit\">Connect</button>\n</form>\n</body>\n</html>\n");
+    page
+}
+
+/// Left blank (the "WPA/WPA2 Personal" option) for a plain PSK network;
+/// picking a method and filling in `eap_identity` switches `handle_connect`
+/// onto the 802.1x path instead of treating `passphrase` as a PSK.
+const EAP_FORM_FIELDS: &str = concat!(
+    "  <select name=\"eap_method\">\n",
+    "    <option value=\"\">WPA/WPA2 Personal</option>\n",
+    "    <option value=\"peap\">WPA/WPA2 Enterprise (PEAP)</option>\n",
+    "    <option value=\"ttls\">WPA/WPA2 Enterprise (TTLS)</option>\n",
+    "    <option value=\"tls\">WPA/WPA2 Enterprise (TLS)</option>\n",
+    "  </select>\n",
+    "  <input name=\"eap_identity\" placeholder=\"EAP identity\">\n",
+    "  <input name=\"eap_anonymous_identity\" placeholder=\"EAP anonymous identity (optional)\">\n",
+    "  <input name=\"eap_ca_cert_path\" placeholder=\"CA certificate path (optional)\">\n"
+);
+
+/// Escapes a value for use inside an HTML attribute. `ExtraField` values are
+/// operator-configured rather than attacker-controlled, but a `"` in a label
+/// would still break out of the attribute it's placed in, so this gets the
+/// same care `json::escape` gets on the JSON side.
+fn html_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Sends `command` to the network thread and blocks for the resulting
+/// `AccessPoints` response. `Rescan` re-reads the access points from
+/// NetworkManager first; `Activate` answers with the list the network
+/// thread last read.
+fn serve_access_points(
+    stream: &mut TcpStream,
+    server_rx: &Receiver<NetworkCommandResponse>,
+    network_tx: &Sender<NetworkCommand>,
+    command: NetworkCommand,
+) {
+    if let Err(e) = network_tx.send(command) {
+        error!("Sending network command failed: {}", e);
+        return respond(stream, 500, "Internal Server Error", "");
+    }
+
+    match server_rx.recv() {
+        Ok(NetworkCommandResponse::AccessPoints(access_points)) => {
+            respond_json(stream, &access_points_to_json(&access_points));
+        },
+        Err(e) => {
+            error!("Receiving access points failed: {}", e);
+            respond(stream, 500, "Internal Server Error", "");
+        },
+    }
+}
+
+fn handle_connect(
+    stream: &mut TcpStream,
+    body: &str,
+    config: &Config,
+    network_tx: &Sender<NetworkCommand>,
+) {
+    let fields = parse_form_body(body);
+
+    let ssid = fields.get("ssid").cloned().unwrap_or_default();
+    let passphrase = fields.get("passphrase").cloned().unwrap_or_default();
+
+    if ssid.is_empty() {
+        return respond(stream, 400, "Bad Request", "");
+    }
+
+    let extra_fields = config
+        .extra_fields
+        .iter()
+        .filter_map(|field| {
+            fields
+                .get(&field.id)
+                .map(|value| (field.id.clone(), value.clone()))
+        })
+        .collect();
+
+    let command = NetworkCommand::Connect {
+        ssid,
+        passphrase,
+        extra_fields,
+        eap: parse_eap_config(&fields),
+    };
+
+    if let Err(e) = network_tx.send(command) {
+        error!("Sending connect command failed: {}", e);
+        return respond(stream, 500, "Internal Server Error", "");
+    }
+
+    respond(stream, 200, "OK", "");
+}
+
+/// Builds an `EapConfig` from the `eap_*` form fields, or `None` when
+/// `eap_method` is left on "WPA/WPA2 Personal" (the default, PSK path) or
+/// `eap_identity` is empty.
+fn parse_eap_config(fields: &HashMap<String, String>) -> Option<EapConfig> {
+    let method = match fields.get("eap_method").map(String::as_str) {
+        Some("peap") => EapMethod::Peap,
+        Some("ttls") => EapMethod::Ttls,
+        Some("tls") => EapMethod::Tls,
+        _ => return None,
+    };
+
+    let identity = fields.get("eap_identity").cloned().unwrap_or_default();
+    if identity.is_empty() {
+        return None;
+    }
+
+    Some(EapConfig {
+        method,
+        identity,
+        anonymous_identity: non_empty(fields.get("eap_anonymous_identity").cloned()),
+        ca_cert_path: non_empty(fields.get("eap_ca_cert_path").cloned()),
+    })
+}
+
+fn non_empty(value: Option<String>) -> Option<String> {
+    match value {
+        Some(ref s) if s.is_empty() => None,
+        other => other,
+    }
+}
+
+fn access_points_to_json(access_points: &[AccessPointInfo]) -> String {
+    let entries = access_points
+        .iter()
+        .map(|ap| {
+            format!(
+                "{{\"ssid\":\"{}\",\"strength\":{},\"security\":\"{}\"}}",
+                json::escape(&ap.ssid),
+                ap.strength,
+                security_label(&ap.security)
+            )
+        })
+        .collect::<Vec<String>>()
+        .join(",");
+
+    format!("[{}]", entries)
+}
+
+fn security_label(security: &AccessPointSecurity) -> &'static str {
+    match *security {
+        AccessPointSecurity::Open => "open",
+        AccessPointSecurity::Wpa => "wpa-psk",
+        AccessPointSecurity::Enterprise => "enterprise",
+    }
+}
+
+fn parse_form_body(body: &str) -> HashMap<String, String> {
+    body.split('&')
+        .filter(|pair| !pair.is_empty())
+        .filter_map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            let key = parts.next()?;
+            let value = parts.next().unwrap_or("");
+            Some((url_decode(key), url_decode(value)))
+        })
+        .collect()
+}
+
+fn url_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => {
+                decoded.push(b' ');
+                i += 1;
+            },
+            b'%' if i + 2 < bytes.len() => {
+                let hex = ::std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 3;
+                    },
+                    None => {
+                        decoded.push(bytes[i]);
+                        i += 1;
+                    },
+                }
+            },
+            b => {
+                decoded.push(b);
+                i += 1;
+            },
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn respond(stream: &mut TcpStream, status: u16, reason: &str, body: &str) {
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    );
+    let _ = stream.write_all(response.as_bytes());
+}
+
+fn respond_json(stream: &mut TcpStream, body: &str) {
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    let _ = stream.write_all(response.as_bytes());
+}